@@ -0,0 +1,226 @@
+use std::{
+    collections::{HashMap, HashSet},
+    hash::{Hash, Hasher},
+    sync::Arc,
+};
+
+use mlua::{Lua, LuaSerdeExt};
+
+use crate::{Environment, LawlError, functions, install_markdown_helpers};
+
+/// Per-template cached render state: a persistent `Lua` interpreter with the
+/// environment's functions already loaded, the shortcode-expanded template
+/// text, and which version of each `Environment::values` entry is currently
+/// installed as a Lua global.
+///
+/// A repeated render skips the shortcode pass and reloading every function,
+/// and reserializes only the values that changed since the last render --
+/// the same cached-or-generate, invalidate-on-change pattern an incremental
+/// document compiler uses. The `<lua>` rewrite pass still parses the expanded
+/// template with `lol_html` on every render; no element-handler plan is cached.
+pub(crate) struct CachedTemplate {
+    fingerprint: u64,
+    lua: Lua,
+    expanded: String,
+    value_versions: HashMap<String, u64>,
+    /// Names of the globals installed at build time (functions, native
+    /// functions, built-in helpers, and everything the sandbox's stdlib
+    /// whitelist opens). Anything outside this set plus the current
+    /// `Environment::values` keys is template-authored state (e.g. a global
+    /// a `<lua>` tag set without `local`) and gets scrubbed before the next
+    /// render, so globals a template creates don't accumulate across calls.
+    /// Only names are compared: a baseline global or an `Environment::values`
+    /// global that a template reassigns keeps its new value in later renders.
+    baseline_globals: HashSet<String>,
+}
+
+impl CachedTemplate {
+    fn build(template: &str, environment: &Environment) -> Result<Self, LawlError> {
+        let expanded = crate::shortcode::expand(template, &environment.shortcodes)?;
+        let lua = environment.sandbox.build_lua();
+
+        for v in &environment.functions {
+            lua.load(v).exec().map_err(|error| LawlError::Lua {
+                message: error.to_string(),
+                location: None,
+            })?;
+        }
+
+        functions::install(&lua, &environment.native_functions);
+        install_markdown_helpers(&lua, environment.markdown_options.clone());
+        crate::each::install(&lua);
+
+        let baseline_globals = global_names(&lua)?;
+
+        Ok(Self {
+            fingerprint: fingerprint(environment),
+            lua,
+            expanded,
+            value_versions: HashMap::new(),
+            baseline_globals,
+        })
+    }
+
+    /// Removes any global that isn't part of `baseline_globals` or a current
+    /// `Environment::values` key, undoing whatever the previous render's
+    /// `<lua>` code left behind in the global table.
+    fn scrub_stray_globals(&mut self, environment: &Environment) -> Result<(), LawlError> {
+        let stray: Vec<String> = global_names(&self.lua)?
+            .into_iter()
+            .filter(|name| {
+                !self.baseline_globals.contains(name) && !environment.values.contains_key(name)
+            })
+            .collect();
+
+        for name in stray {
+            self.lua
+                .globals()
+                .set(name.as_str(), mlua::Value::Nil)
+                .expect("Unable to remove global.");
+        }
+
+        Ok(())
+    }
+
+    /// Re-serializes and re-installs only the `Environment::values` entries
+    /// whose version changed since the last render, after scrubbing stray
+    /// globals left by the previous render.
+    fn sync_values(&mut self, environment: &Environment) -> Result<(), LawlError> {
+        self.scrub_stray_globals(environment)?;
+
+        self.value_versions
+            .retain(|k, _| environment.values.contains_key(k));
+
+        for (k, wrapper) in &environment.values {
+            let current_version = environment.value_versions.get(k).copied().unwrap_or(0);
+
+            if self.value_versions.get(k) == Some(&current_version) {
+                continue;
+            }
+
+            let value = wrapper.lock().unwrap();
+            let lua_value = self
+                .lua
+                .to_value(&value.as_ref())
+                .map_err(|error| LawlError::Serialization(error.to_string()))?;
+            self.lua
+                .globals()
+                .set(k.as_str(), lua_value)
+                .expect("Unable to assign globals.");
+
+            self.value_versions.insert(k.clone(), current_version);
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn unset_global(&mut self, key: &str) {
+        self.value_versions.remove(key);
+        self.lua
+            .globals()
+            .set(key, mlua::Value::Nil)
+            .expect("Unable to remove global.");
+    }
+}
+
+/// Fingerprints everything about `Environment` that changes what gets
+/// compiled into a `CachedTemplate`'s Lua instance (but not `values`, which
+/// are tracked per-key with their own version counters instead).
+fn fingerprint(environment: &Environment) -> u64 {
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+
+    environment.functions.hash(&mut hasher);
+
+    // Hash each name alongside the closure's `Arc` pointer identity, not
+    // just the name, so re-registering a different closure under a name
+    // that's already cached (`register_function("shout", new_fn)`) is seen
+    // as a change instead of leaving every cached template silently running
+    // the stale closure.
+    let mut native_functions: Vec<(&String, *const ())> = environment
+        .native_functions
+        .iter()
+        .map(|(name, f)| (name, Arc::as_ptr(f) as *const ()))
+        .collect();
+    native_functions.sort_by_key(|(name, _)| *name);
+    native_functions.hash(&mut hasher);
+
+    let mut shortcodes: Vec<(&String, &String)> = environment.shortcodes.iter().collect();
+    shortcodes.sort();
+    shortcodes.hash(&mut hasher);
+
+    environment.sandbox.fingerprint().hash(&mut hasher);
+    environment.markdown_options.hash(&mut hasher);
+
+    hasher.finish()
+}
+
+fn hash_template(template: &str) -> u64 {
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    template.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Snapshots the names currently set on `lua`'s global table.
+fn global_names(lua: &Lua) -> Result<HashSet<String>, LawlError> {
+    lua.globals()
+        .pairs::<String, mlua::Value>()
+        .map(|pair| pair.map(|(name, _)| name))
+        .collect::<mlua::Result<HashSet<String>>>()
+        .map_err(|error| LawlError::Lua {
+            message: error.to_string(),
+            location: None,
+        })
+}
+
+#[derive(Default)]
+pub(crate) struct RenderCache {
+    entries: HashMap<u64, CachedTemplate>,
+}
+
+impl RenderCache {
+    /// Returns the (persistent Lua, expanded template text) for `template`
+    /// under `environment`, rebuilding the cached entry if the template text
+    /// or anything other than `values` changed, and reserializing only the
+    /// `values` entries that changed.
+    ///
+    /// The caller must keep `RenderCache`'s own lock held for the duration of
+    /// the actual render, not just this lookup -- the global scrub/sync above
+    /// mutates the same persistent `Lua` instance that gets executed
+    /// afterwards, so releasing the lock in between would let a second
+    /// render for the same template scrub or re-sync globals out from under
+    /// Lua code that's still running.
+    pub(crate) fn get(
+        &mut self,
+        template: &str,
+        environment: &Environment,
+    ) -> Result<(Lua, String), LawlError> {
+        let key = hash_template(template);
+        let current_fingerprint = fingerprint(environment);
+
+        let needs_rebuild = match self.entries.get(&key) {
+            Some(entry) => entry.fingerprint != current_fingerprint,
+            None => true,
+        };
+
+        if needs_rebuild {
+            self.entries
+                .insert(key, CachedTemplate::build(template, environment)?);
+        }
+
+        let entry = self
+            .entries
+            .get_mut(&key)
+            .expect("entry was just built or already present");
+        entry.sync_values(environment)?;
+
+        Ok((entry.lua.clone(), entry.expanded.clone()))
+    }
+
+    /// Invalidates the cached Lua global for `key` across every cached
+    /// template, called from `Lawl::remove`.
+    pub(crate) fn unset_global(&mut self, key: &str) {
+        for entry in self.entries.values_mut() {
+            entry.unset_global(key);
+        }
+    }
+}
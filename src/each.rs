@@ -0,0 +1,150 @@
+use std::cmp::Ordering;
+
+use mlua::{Lua, LuaSerdeExt, Table, Value};
+
+/// Installs the `each(items, options)` global, replacing the old pure-Lua
+/// `each(k)` with a Rust-backed implementation that can sort, filter and
+/// limit the collection before expanding it.
+///
+/// `options` is an optional table supporting:
+/// - `sort = '<field>'`: stably sort by that field's value.
+/// - `order = 'asc' | 'desc'` (default `'asc'`).
+/// - `where = function(item) ... end`: keep only items the predicate accepts.
+/// - `take = <n>`: slice to the first `n` items after sorting/filtering.
+///
+/// As before, `data` is read as the per-item template and rebuilt by
+/// substituting `$field` placeholders for each surviving item, in order.
+pub(crate) fn install(lua: &Lua) {
+    let each_fn = lua
+        .create_function(|lua, (items, options): (Table, Option<Table>)| {
+            let data: String = lua.globals().get("data")?;
+
+            let mut entries: Vec<Table> = items
+                .sequence_values::<Table>()
+                .collect::<mlua::Result<_>>()?;
+
+            if let Some(options) = &options {
+                if let Ok(predicate) = options.get::<_, mlua::Function>("where") {
+                    let mut kept = Vec::with_capacity(entries.len());
+                    for item in entries {
+                        if predicate.call::<_, bool>(item.clone())? {
+                            kept.push(item);
+                        }
+                    }
+                    entries = kept;
+                }
+
+                if let Ok(field) = options.get::<_, String>("sort") {
+                    let descending = options
+                        .get::<_, String>("order")
+                        .map(|order| order.eq_ignore_ascii_case("desc"))
+                        .unwrap_or(false);
+
+                    let mut keyed: Vec<(serde_json::Value, Table)> = entries
+                        .into_iter()
+                        .map(|item| {
+                            let value: Value = item.get(field.as_str())?;
+                            Ok::<_, mlua::Error>((lua.from_value(value)?, item))
+                        })
+                        .collect::<mlua::Result<_>>()?;
+
+                    keyed.sort_by(|(a, _), (b, _)| compare(a, b, descending));
+                    entries = keyed.into_iter().map(|(_, item)| item).collect();
+                }
+
+                if let Ok(take) = options.get::<_, usize>("take") {
+                    entries.truncate(take);
+                }
+            }
+
+            let mut output = String::new();
+            for item in &entries {
+                output.push_str(&expand(&data, item)?);
+            }
+
+            lua.globals().set("data", output)
+        })
+        .expect("Unable to create each() helper.");
+
+    lua.globals()
+        .set("each", each_fn)
+        .expect("Unable to assign globals.");
+}
+
+/// Substitutes every `$field` placeholder in `template` with `item`'s value
+/// for that field, leaving the placeholder untouched when the field is nil
+/// -- the same behavior `template:gsub('%$([a-zA-Z_]+)', item)` had.
+fn expand(template: &str, item: &Table) -> mlua::Result<String> {
+    let mut output = String::new();
+    let mut i = 0;
+
+    while i < template.len() {
+        if template.as_bytes()[i] == b'$' {
+            let rest = &template[i + 1..];
+            let end = rest
+                .find(|c: char| !(c.is_ascii_alphabetic() || c == '_'))
+                .unwrap_or(rest.len());
+
+            if end > 0 {
+                let field = &rest[..end];
+                let value: Value = item.get(field)?;
+
+                match value {
+                    Value::Nil => output.push_str(&template[i..i + 1 + end]),
+                    other => output.push_str(&display(&other)),
+                }
+
+                i += 1 + end;
+                continue;
+            }
+        }
+
+        let c = template[i..].chars().next().unwrap();
+        output.push(c);
+        i += c.len_utf8();
+    }
+
+    Ok(output)
+}
+
+fn display(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.to_str().map(|s| s.to_string()).unwrap_or_default(),
+        Value::Integer(i) => i.to_string(),
+        Value::Number(n) => n.to_string(),
+        Value::Boolean(b) => b.to_string(),
+        _ => String::new(),
+    }
+}
+
+/// Natural ordering over deserialized field values: numbers numerically,
+/// strings lexicographically, with every number before every string. Any
+/// other value (a missing field, a boolean, a table) sorts last. `descending`
+/// reverses only the order within numbers and within strings, not this
+/// grouping by type.
+fn rank(value: &serde_json::Value) -> u8 {
+    match value {
+        serde_json::Value::Number(_) => 0,
+        serde_json::Value::String(_) => 1,
+        _ => 2,
+    }
+}
+
+fn compare(a: &serde_json::Value, b: &serde_json::Value, descending: bool) -> Ordering {
+    let (rank_a, rank_b) = (rank(a), rank(b));
+
+    if rank_a != rank_b {
+        return rank_a.cmp(&rank_b);
+    }
+
+    let ordering = match rank_a {
+        0 => a
+            .as_f64()
+            .partial_cmp(&b.as_f64())
+            .unwrap_or(Ordering::Equal),
+        1 => a.as_str().cmp(&b.as_str()),
+        _ => Ordering::Equal,
+    };
+
+    if descending { ordering.reverse() } else { ordering }
+}
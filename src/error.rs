@@ -0,0 +1,84 @@
+use std::fmt::{self, Display, Formatter};
+
+/// Everything that can go wrong rendering a template, in place of the
+/// `Result<_, ()>` (and outright panics) the renderer used to rely on.
+#[derive(Debug)]
+pub enum LawlError {
+    /// A Lua chunk failed to load or raised an error while executing,
+    /// e.g. a malformed `code` expression inside a `<lua>` tag.
+    Lua {
+        message: String,
+        /// Where in the shortcode-expanded template the offending `<lua>`
+        /// tag's content begins (just past its opening tag), when the error
+        /// can be tied to one.
+        location: Option<SourceLocation>,
+    },
+    /// `LuaSerdeExt::to_value`/`from_value` failed to convert between a
+    /// Rust value and its Lua representation.
+    Serialization(String),
+    /// `lol_html` failed to rewrite the template.
+    HtmlRewrite(String),
+    /// The rewritten output wasn't valid UTF-8.
+    Utf8(std::string::FromUtf8Error),
+}
+
+/// A byte offset in a template, recovered into a 1-indexed line/column pair
+/// so callers get a diagnostic pointing at the exact template location.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SourceLocation {
+    pub byte_offset: usize,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl SourceLocation {
+    pub(crate) fn in_source(source: &str, byte_offset: usize) -> Self {
+        let mut line = 1;
+        let mut column = 1;
+
+        for c in source[..byte_offset.min(source.len())].chars() {
+            if c == '\n' {
+                line += 1;
+                column = 1;
+            } else {
+                column += 1;
+            }
+        }
+
+        Self {
+            byte_offset,
+            line,
+            column,
+        }
+    }
+}
+
+impl Display for LawlError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            LawlError::Lua {
+                message,
+                location: Some(location),
+            } => write!(
+                f,
+                "Lua error at line {}, column {} (byte {}): {message}",
+                location.line, location.column, location.byte_offset
+            ),
+            LawlError::Lua {
+                message,
+                location: None,
+            } => write!(f, "Lua error: {message}"),
+            LawlError::Serialization(message) => write!(f, "Serialization error: {message}"),
+            LawlError::HtmlRewrite(message) => write!(f, "HTML rewrite error: {message}"),
+            LawlError::Utf8(error) => write!(f, "UTF-8 error: {error}"),
+        }
+    }
+}
+
+impl std::error::Error for LawlError {}
+
+impl From<std::string::FromUtf8Error> for LawlError {
+    fn from(error: std::string::FromUtf8Error) -> Self {
+        LawlError::Utf8(error)
+    }
+}
@@ -0,0 +1,35 @@
+use std::{collections::HashMap, sync::Arc};
+
+use mlua::{Lua, LuaSerdeExt, Variadic};
+
+/// A Rust closure callable from inside a `<lua>` tag.
+///
+/// Arguments are deserialized from Lua into `serde_json::Value`s and the
+/// return value is serialized back the same way, via `LuaSerdeExt`.
+pub type NativeFunction = Arc<dyn Fn(Vec<serde_json::Value>) -> serde_json::Value + Sync + Send>;
+
+/// Installs every registered native function as a Lua global on `lua`.
+///
+/// Called once when a template's cached, persistent `Lua` interpreter is
+/// built; registering a function again changes the environment fingerprint,
+/// so the interpreter is rebuilt on the next `render`.
+pub(crate) fn install(lua: &Lua, native_functions: &HashMap<String, NativeFunction>) {
+    for (name, f) in native_functions {
+        let f = f.clone();
+
+        let func = lua
+            .create_function(move |lua, args: Variadic<mlua::Value>| {
+                let args = args
+                    .iter()
+                    .map(|v| lua.from_value(v.clone()))
+                    .collect::<mlua::Result<Vec<serde_json::Value>>>()?;
+
+                lua.to_value(&f(args))
+            })
+            .expect("Unable to create native function.");
+
+        lua.globals()
+            .set(name.as_str(), func)
+            .expect("Unable to assign globals.");
+    }
+}
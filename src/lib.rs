@@ -1,42 +1,119 @@
-use std::{collections::HashMap, fmt::Display, sync::Mutex};
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    fmt::Display,
+    rc::Rc,
+    sync::{Arc, Mutex},
+};
 
 use erased_serde::Serialize;
 use lol_html::{HtmlRewriter, Settings, element, html_content::Element};
-use mlua::{Lua, LuaSerdeExt};
+use mlua::Lua;
+
+mod cache;
+mod each;
+mod error;
+mod functions;
+mod markdown;
+mod sandbox;
+mod shortcode;
+
+use cache::RenderCache;
+pub use error::{LawlError, SourceLocation};
+pub use functions::NativeFunction;
+pub use markdown::MarkdownOptions;
+pub use sandbox::SandboxConfig;
 
 pub struct Lawl {
     environment: Environment,
+    cache: Mutex<RenderCache>,
+    next_value_version: u64,
 }
 
 type Value = Box<dyn Serialize + Sync + Send>;
 type Wrapper<T> = Mutex<T>;
 
 impl Lawl {
-    pub fn render(&self, html: &impl Display) -> Result<String, ()> {
-        html.to_string().render(&self.environment)
+    pub fn render(&self, html: &impl Display) -> Result<String, LawlError> {
+        html.to_string().render(&self.environment, &self.cache)
     }
 
     pub fn insert<T: Serialize + Sync + Send + 'static>(
         &mut self,
         key: &impl Display,
         value: T,
-    ) -> Result<(), ()> {
+    ) -> Result<(), LawlError> {
+        let key = key.to_string();
+
+        self.next_value_version += 1;
+        self.environment
+            .value_versions
+            .insert(key.clone(), self.next_value_version);
         self.environment
             .values
-            .insert(key.to_string(), Mutex::new(Box::new(value)));
+            .insert(key, Mutex::new(Box::new(value)));
         Ok(())
     }
 
-    pub fn remove(&mut self, key: &impl Display) -> Result<(), ()> {
-        self.environment.values.remove(&key.to_string());
+    pub fn remove(&mut self, key: &impl Display) -> Result<(), LawlError> {
+        let key = key.to_string();
+
+        self.environment.values.remove(&key);
+        self.environment.value_versions.remove(&key);
+        self.cache.lock().unwrap().unset_global(&key);
         Ok(())
     }
+
+    /// Registers a Rust closure under `name` so templates can call it from
+    /// inside a `<lua>` tag, e.g. `<lua code='data = slugify(data)'>`.
+    ///
+    /// Arguments passed from Lua are deserialized into `serde_json::Value`s
+    /// and the closure's return value is serialized back through
+    /// `LuaSerdeExt`, so the function reads like any other host binding
+    /// exposed to an embedded script. The registry is installed as Lua
+    /// globals when a template's cached interpreter is built, and registering
+    /// a name again rebuilds that interpreter on the next `render` call.
+    pub fn register_function<F>(&mut self, name: impl Display, f: F)
+    where
+        F: Fn(Vec<serde_json::Value>) -> serde_json::Value + Sync + Send + 'static,
+    {
+        self.environment
+            .native_functions
+            .insert(name.to_string(), Arc::new(f));
+    }
+
+    /// Replaces the default safe Lua sandbox with `config`, e.g. to opt a
+    /// trusted template author into `io`/`os` access or to cap how long a
+    /// template's `<lua>` code may run.
+    pub fn with_sandbox(mut self, config: SandboxConfig) -> Self {
+        self.environment.sandbox = config;
+        self
+    }
+
+    /// Registers a named, parameterized HTML fragment that can be invoked
+    /// from a template as `<shortcode name="..." attr="...">`, instead of
+    /// repeating markup or abusing the `each` helper. Attributes passed at
+    /// the call site are exposed to the fragment's own `<lua>` tags.
+    pub fn register_shortcode(&mut self, name: impl Display, template_fragment: impl Display) {
+        self.environment
+            .shortcodes
+            .insert(name.to_string(), template_fragment.to_string());
+    }
+
+    /// Configures the CommonMark extensions the built-in `markdown()`
+    /// helper enables, e.g. tables or strikethrough. Disabled by default.
+    pub fn with_markdown(mut self, options: MarkdownOptions) -> Self {
+        self.environment.markdown_options = options;
+        self
+    }
 }
 
 impl Default for Lawl {
     fn default() -> Self {
         Self {
             environment: Default::default(),
+            cache: Mutex::new(RenderCache::default()),
+            next_value_version: 0,
         }
     }
 }
@@ -44,53 +121,87 @@ impl Default for Lawl {
 pub struct Environment {
     pub values: HashMap<String, Wrapper<Value>>,
     pub functions: Vec<String>,
+    pub native_functions: HashMap<String, NativeFunction>,
+    pub sandbox: SandboxConfig,
+    pub shortcodes: HashMap<String, String>,
+    pub markdown_options: MarkdownOptions,
+    /// Monotonic version per `values` key, bumped on every `Lawl::insert`.
+    /// `RenderCache` compares this against what it last installed as a Lua
+    /// global to decide whether a value needs to be re-serialized.
+    pub value_versions: HashMap<String, u64>,
 }
 
 impl Default for Environment {
     fn default() -> Self {
         Self {
             values: HashMap::new(),
+            native_functions: HashMap::new(),
+            sandbox: SandboxConfig::default(),
+            shortcodes: HashMap::new(),
+            markdown_options: MarkdownOptions::default(),
+            value_versions: HashMap::new(),
             functions: vec![
                 "function show(v) if (v or '') == '' then data = '' end end".to_string(),
                 "function hide(v) if (v or '') ~= '' then data = '' end end".to_string(),
                 "function maybe(v, o) return v or o end".to_string(),
                 "function format(...) data = string.format(data, ...) end".to_string(),
-                "function each(k) local template = data; data = ''; for _, post in ipairs(k) do data = data .. template:gsub('%$([a-zA-Z_]+)', post) end end".to_string()
             ],
         }
     }
 }
 
 trait Render {
-    fn render(&self, environment: &Environment) -> Result<String, ()>;
+    fn render(
+        &self,
+        environment: &Environment,
+        cache: &Mutex<RenderCache>,
+    ) -> Result<String, LawlError>;
 }
 
 impl Render for String {
-    fn render(&self, environment: &Environment) -> Result<String, ()> {
-        let mut env = vec![];
-        let lua = Lua::new();
-
-        for (k, v) in &environment.values {
-            let value = v.lock().unwrap();
-
-            let value = lua.to_value(&value.as_ref()).unwrap();
-            env.push((k.to_owned(), value));
-        }
-
-        for v in &environment.functions {
-            lua.load(v).exec().unwrap();
-        }
-
-        for (k, v) in env {
-            lua.globals().set(k, v).expect("Unable to assign globals.")
-        }
-
-        render(self, lua)
+    fn render(
+        &self,
+        environment: &Environment,
+        cache: &Mutex<RenderCache>,
+    ) -> Result<String, LawlError> {
+        // Held across the scrub/sync *and* the actual Lua execution below,
+        // not just the lookup -- releasing it in between would let a second
+        // render for this same template scrub or re-sync globals on the
+        // shared persistent `Lua` instance while the first render is still
+        // mid-execution against it.
+        let mut cache = cache.lock().unwrap();
+        let (lua, expanded) = cache.get(self, environment)?;
+
+        render(&expanded, lua)
     }
 }
 
-fn render(template: &String, lua: Lua) -> Result<String, ()> {
+/// Installs the built-in `markdown()`/`slugify()` globals, mirroring how
+/// `show`/`hide`/`format` are installed from `Environment::functions`, but
+/// backed by Rust instead of Lua source.
+fn install_markdown_helpers(lua: &Lua, options: MarkdownOptions) {
+    let markdown_fn = lua
+        .create_function(move |lua, _: mlua::Variadic<mlua::Value>| {
+            let data: String = lua.globals().get("data")?;
+            lua.globals().set("data", markdown::render(&data, &options))
+        })
+        .expect("Unable to create markdown() helper.");
+    lua.globals()
+        .set("markdown", markdown_fn)
+        .expect("Unable to assign globals.");
+
+    let slugify_fn = lua
+        .create_function(|_, text: String| Ok(markdown::slugify(&text)))
+        .expect("Unable to create slugify() helper.");
+    lua.globals()
+        .set("slugify", slugify_fn)
+        .expect("Unable to assign globals.");
+}
+
+fn render(template: &String, lua: Lua) -> Result<String, LawlError> {
     let mut buffer = vec![];
+    let lua_error: Rc<RefCell<Option<LawlError>>> = Rc::new(RefCell::new(None));
+
     let mut rewriter = HtmlRewriter::new(
         Settings {
             element_content_handlers: vec![element!("lua", |el: &mut Element| {
@@ -101,18 +212,45 @@ fn render(template: &String, lua: Lua) -> Result<String, ()> {
                     let source = template.clone();
                     let e = expression.clone();
                     let lua = lua.clone();
+                    let lua_error = lua_error.clone();
 
                     handlers.push(Box::new(move |end| {
                         let end_location = end.source_location().bytes().start;
                         let html = source[start_location..end_location].to_string();
 
-                        lua.globals().set("data", html).unwrap();
-
-                        lua.load(&e)
-                            .exec()
-                            .expect(format!("Invalid Lua expression. {}", e).as_str());
-
-                        let data: String = lua.globals().get("data").unwrap();
+                        if let Err(error) = lua.globals().set("data", html) {
+                            *lua_error.borrow_mut() = Some(LawlError::Lua {
+                                message: format!(
+                                    "failed to set `data` before the <lua> tag runs: {error}"
+                                ),
+                                location: Some(SourceLocation::in_source(&source, start_location)),
+                            });
+                            return Err("invalid Lua expression".into());
+                        }
+
+                        if let Err(error) = lua.load(&e).exec() {
+                            *lua_error.borrow_mut() = Some(LawlError::Lua {
+                                message: error.to_string(),
+                                location: Some(SourceLocation::in_source(&source, start_location)),
+                            });
+                            return Err("invalid Lua expression".into());
+                        }
+
+                        let data: String = match lua.globals().get("data") {
+                            Ok(data) => data,
+                            Err(error) => {
+                                *lua_error.borrow_mut() = Some(LawlError::Lua {
+                                    message: format!(
+                                        "`data` must be a string after the <lua> tag runs: {error}"
+                                    ),
+                                    location: Some(SourceLocation::in_source(
+                                        &source,
+                                        start_location,
+                                    )),
+                                });
+                                return Err("invalid Lua expression".into());
+                            }
+                        };
 
                         end.before(&data, lol_html::html_content::ContentType::Html);
 
@@ -126,9 +264,14 @@ fn render(template: &String, lua: Lua) -> Result<String, ()> {
         |c: &[u8]| buffer.extend_from_slice(c),
     );
 
-    rewriter.write(template.as_bytes()).unwrap();
+    if let Err(error) = rewriter.write(template.as_bytes()) {
+        return Err(lua_error
+            .borrow_mut()
+            .take()
+            .unwrap_or_else(|| LawlError::HtmlRewrite(error.to_string())));
+    }
 
-    Ok(String::from_utf8(buffer).unwrap())
+    Ok(String::from_utf8(buffer)?)
 }
 
 #[cfg(test)]
@@ -167,4 +310,202 @@ mod tests {
 
         debug_assert_eq!("my little pony".to_string(), lawl.render(&html).unwrap())
     }
+
+    #[test]
+    fn should_not_expose_os_library_by_default() {
+        let lawl = Lawl::default();
+
+        let html = r#"<lua code='data = os.time()'>replace me!</lua>"#.to_string();
+
+        match lawl.render(&html) {
+            Err(LawlError::Lua { location, .. }) => assert!(location.is_some()),
+            other => panic!("expected a located Lua error, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn should_return_located_error_when_data_is_not_a_string_after_lua_runs() {
+        let lawl = Lawl::default();
+
+        let html = r#"<lua code='data = nil'>replace me!</lua>"#.to_string();
+
+        match lawl.render(&html) {
+            Err(LawlError::Lua { location, .. }) => assert!(location.is_some()),
+            other => panic!("expected a located Lua error, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn should_expose_os_library_when_opted_in() {
+        let lawl = Lawl::default().with_sandbox(SandboxConfig::default().with_stdlib(mlua::StdLib::ALL_SAFE));
+
+        let html = r#"<lua code='data = tostring(os.time() > 0)'>replace me!</lua>"#.to_string();
+
+        debug_assert_eq!("true".to_string(), lawl.render(&html).unwrap());
+    }
+
+    #[test]
+    fn should_expand_shortcode_and_bind_attributes_for_its_lua_tags() {
+        let mut lawl = Lawl::default();
+
+        lawl.register_shortcode(
+            "greeting",
+            r#"<lua code='data = "Hello, " .. subject'>replace me!</lua>"#,
+        );
+
+        let html = r#"<shortcode name="greeting" subject="World" />"#.to_string();
+
+        debug_assert_eq!("Hello, World".to_string(), lawl.render(&html).unwrap());
+    }
+
+    #[test]
+    fn should_escape_quotes_and_angle_brackets_in_shortcode_attribute_values() {
+        let mut lawl = Lawl::default();
+
+        lawl.register_shortcode(
+            "greeting",
+            r#"<lua code='data = "Hello, " .. subject'>replace me!</lua>"#,
+        );
+
+        // A `'` in the attribute value would, if spliced unescaped into the
+        // single-quoted `<lua code='...'>` binding tag, close that attribute
+        // early and let the rest of the value be reparsed as new markup —
+        // including a brand new `<lua>` tag with attacker-controlled code.
+        let html = r#"<shortcode name="greeting" subject="it's <b>unsafe</b>" />"#.to_string();
+
+        debug_assert_eq!(
+            "Hello, it's <b>unsafe</b>".to_string(),
+            lawl.render(&html).unwrap()
+        );
+    }
+
+    #[test]
+    fn should_render_markdown_to_html_with_heading_anchor() {
+        let lawl = Lawl::default();
+
+        let html = "<lua code='markdown()'>## Hello World</lua>".to_string();
+
+        debug_assert_eq!(
+            "<h2 id=\"hello-world\">Hello World</h2>\n".to_string(),
+            lawl.render(&html).unwrap()
+        );
+    }
+
+    #[test]
+    fn should_slug_heading_from_decoded_entities_not_escaped_html() {
+        let lawl = Lawl::default();
+
+        let html = "<lua code='markdown()'>## Q&A</lua>".to_string();
+
+        debug_assert_eq!(
+            "<h2 id=\"q-a\">Q&amp;A</h2>\n".to_string(),
+            lawl.render(&html).unwrap()
+        );
+    }
+
+    #[test]
+    fn should_reflect_updated_and_removed_values_across_cached_renders() {
+        let mut lawl = Lawl::default();
+
+        lawl.insert(&"name", "Ferris".to_string()).unwrap();
+
+        let html = r#"<lua code="data = name">replace me!</lua>"#.to_string();
+
+        debug_assert_eq!("Ferris".to_string(), lawl.render(&html).unwrap());
+
+        lawl.insert(&"name", "Crab".to_string()).unwrap();
+
+        debug_assert_eq!("Crab".to_string(), lawl.render(&html).unwrap());
+
+        lawl.remove(&"name").unwrap();
+
+        let reads_name_or_gone =
+            r#"<lua code='data = name == nil and "gone" or name'>replace me!</lua>"#.to_string();
+
+        // Prime the cache entry for this template with `name` still unset...
+        debug_assert_eq!("gone".to_string(), lawl.render(&reads_name_or_gone).unwrap());
+
+        lawl.insert(&"name", "Ferris".to_string()).unwrap();
+        debug_assert_eq!(
+            "Ferris".to_string(),
+            lawl.render(&reads_name_or_gone).unwrap()
+        );
+
+        lawl.remove(&"name").unwrap();
+
+        // ...then confirm a *previously cached* template also sees the
+        // removal, not just a freshly-built one.
+        debug_assert_eq!("gone".to_string(), lawl.render(&reads_name_or_gone).unwrap());
+    }
+
+    #[test]
+    fn should_not_leak_template_authored_globals_across_cached_renders() {
+        let lawl = Lawl::default();
+
+        // `counter` is set without `local`, so it lands in the Lua globals
+        // table shared by the persistent, cached interpreter. A fresh
+        // `Lua::new()`-per-render design would start this at `nil` every
+        // time; the cache must scrub it back to that same clean slate.
+        let html = r#"<lua code='counter = (counter or 0) + 1; data = tostring(counter)'>replace me!</lua>"#.to_string();
+
+        debug_assert_eq!("1".to_string(), lawl.render(&html).unwrap());
+        debug_assert_eq!("1".to_string(), lawl.render(&html).unwrap());
+        debug_assert_eq!("1".to_string(), lawl.render(&html).unwrap());
+    }
+
+    #[test]
+    fn should_sort_filter_and_take_in_each() {
+        let lawl = Lawl::default();
+
+        let html = r#"<lua code='each(posts, { sort = "date", order = "desc", take = 2, where = function(p) return p.published end })'>$title </lua>"#.to_string();
+
+        let script = format!(
+            r#"<lua code='posts = {{
+                {{ title = "a", date = 1, published = true }},
+                {{ title = "b", date = 3, published = true }},
+                {{ title = "c", date = 2, published = false }},
+                {{ title = "d", date = 4, published = true }}
+            }}'></lua>{html}"#
+        );
+
+        // Newest-first (desc by date), unpublished "c" dropped, top 2 kept: d (4), b (3).
+        debug_assert_eq!("d b ".to_string(), lawl.render(&script).unwrap());
+    }
+
+    #[test]
+    fn should_call_registered_native_function() {
+        let mut lawl = Lawl::default();
+
+        lawl.register_function("shout", |args| {
+            let text = args[0].as_str().unwrap_or("").to_uppercase();
+            serde_json::Value::String(text)
+        });
+
+        let html = r#"<lua code='data = shout(data)'>hello</lua>"#.to_string();
+
+        debug_assert_eq!("HELLO".to_string(), lawl.render(&html).unwrap());
+    }
+
+    #[test]
+    fn should_pick_up_a_re_registered_native_function_in_a_cached_template() {
+        let mut lawl = Lawl::default();
+
+        lawl.register_function("shout", |args| {
+            let text = args[0].as_str().unwrap_or("").to_uppercase();
+            serde_json::Value::String(text)
+        });
+
+        let html = r#"<lua code='data = shout(data)'>hello</lua>"#.to_string();
+
+        debug_assert_eq!("HELLO".to_string(), lawl.render(&html).unwrap());
+
+        // Same name, different closure: the cached entry built for `html`
+        // above must not keep running the stale closure.
+        lawl.register_function("shout", |args| {
+            let text = args[0].as_str().unwrap_or("").to_lowercase();
+            serde_json::Value::String(format!("({text})"))
+        });
+
+        debug_assert_eq!("(hello)".to_string(), lawl.render(&html).unwrap());
+    }
 }
@@ -0,0 +1,128 @@
+use pulldown_cmark::{Options, Parser, html};
+
+/// Which CommonMark extensions the built-in `markdown()` helper enables.
+#[derive(Clone, Default, Hash)]
+pub struct MarkdownOptions {
+    pub tables: bool,
+    pub strikethrough: bool,
+    pub footnotes: bool,
+}
+
+impl MarkdownOptions {
+    fn to_cmark_options(&self) -> Options {
+        let mut options = Options::empty();
+
+        if self.tables {
+            options.insert(Options::ENABLE_TABLES);
+        }
+
+        if self.strikethrough {
+            options.insert(Options::ENABLE_STRIKETHROUGH);
+        }
+
+        if self.footnotes {
+            options.insert(Options::ENABLE_FOOTNOTES);
+        }
+
+        options
+    }
+}
+
+/// Renders `markdown` to HTML, giving every heading a slug `id` so the
+/// result can be linked to with anchor links. Raw HTML in the Markdown
+/// source is passed through unchanged; the output is not sanitized.
+pub(crate) fn render(markdown: &str, options: &MarkdownOptions) -> String {
+    let parser = Parser::new_ext(markdown, options.to_cmark_options());
+
+    let mut html_output = String::new();
+    html::push_html(&mut html_output, parser);
+
+    slugify_headings(&html_output)
+}
+
+fn slugify_headings(html: &str) -> String {
+    let mut output = String::with_capacity(html.len());
+    let mut i = 0;
+
+    while i < html.len() {
+        if let Some(level) = heading_level_at(&html[i..]) {
+            let open_tag = format!("<h{level}>");
+            let close_tag = format!("</h{level}>");
+            let open_tag_end = i + open_tag.len();
+
+            if let Some(offset) = html[open_tag_end..].find(&close_tag) {
+                let close_start = open_tag_end + offset;
+                let inner = &html[open_tag_end..close_start];
+                let slug = slugify(&decode_entities(&strip_tags(inner)));
+
+                output.push_str(&format!("<h{level} id=\"{slug}\">"));
+                output.push_str(inner);
+                output.push_str(&close_tag);
+
+                i = close_start + close_tag.len();
+                continue;
+            }
+        }
+
+        let c = html[i..].chars().next().unwrap();
+        output.push(c);
+        i += c.len_utf8();
+    }
+
+    output
+}
+
+fn heading_level_at(html: &str) -> Option<u8> {
+    (1..=6u8).find(|level| html.starts_with(&format!("<h{level}>")))
+}
+
+fn strip_tags(html: &str) -> String {
+    let mut output = String::with_capacity(html.len());
+    let mut in_tag = false;
+
+    for c in html.chars() {
+        match c {
+            '<' => in_tag = true,
+            '>' => in_tag = false,
+            _ if !in_tag => output.push(c),
+            _ => {}
+        }
+    }
+
+    output
+}
+
+/// Decodes the handful of named/numeric entities `pulldown-cmark`'s HTML
+/// escaper produces (`&amp;`, `&lt;`, `&gt;`, `&quot;`, `&#39;`), so a heading
+/// like `## Q&A` slugifies from its original text instead of from the
+/// escaped `Q&amp;A` markup. `&amp;` is decoded last so it can't turn a
+/// literal `&lt;` in the source text into a spurious `<`.
+fn decode_entities(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&amp;", "&")
+}
+
+/// Turns arbitrary text into a lowercase, hyphenated anchor-link slug.
+pub(crate) fn slugify(text: &str) -> String {
+    let mut slug = String::with_capacity(text.len());
+    let mut last_was_dash = true;
+
+    for c in text.chars().flat_map(char::to_lowercase) {
+        if c.is_alphanumeric() {
+            slug.push(c);
+            last_was_dash = false;
+        } else if !last_was_dash {
+            slug.push('-');
+            last_was_dash = true;
+        }
+    }
+
+    while slug.ends_with('-') {
+        slug.pop();
+    }
+
+    slug
+}
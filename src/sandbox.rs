@@ -0,0 +1,82 @@
+use mlua::{HookTriggers, Lua, LuaOptions, StdLib};
+
+/// Controls which Lua standard libraries a template's `<lua>` tags can see,
+/// and optional runtime limits so a runaway template can't hang the host.
+///
+/// Mirrors selectively calling `luaopen_base`/`luaopen_table`/... rather than
+/// opening everything the way a bare `Lua::new()` does. The default excludes
+/// `io`, `os` and `package`; enable them explicitly via [`SandboxConfig::with_stdlib`]
+/// when templates are trusted.
+pub struct SandboxConfig {
+    stdlib: StdLib,
+    memory_limit: Option<usize>,
+    instruction_limit: Option<u32>,
+}
+
+impl Default for SandboxConfig {
+    fn default() -> Self {
+        Self {
+            stdlib: StdLib::BASE | StdLib::STRING | StdLib::TABLE | StdLib::MATH | StdLib::COROUTINE,
+            memory_limit: None,
+            instruction_limit: None,
+        }
+    }
+}
+
+impl SandboxConfig {
+    /// Replaces the whitelisted standard libraries. Pass `StdLib::ALL_SAFE`
+    /// to opt back into every library a bare `Lua::new()` opens, including
+    /// `io`/`os`/`package`.
+    pub fn with_stdlib(mut self, stdlib: StdLib) -> Self {
+        self.stdlib = stdlib;
+        self
+    }
+
+    /// Caps total Lua heap usage, in bytes. Exceeding it raises a Lua error
+    /// instead of growing unbounded.
+    pub fn with_memory_limit(mut self, bytes: usize) -> Self {
+        self.memory_limit = Some(bytes);
+        self
+    }
+
+    /// Aborts a template's `<lua>` execution once it has run roughly this
+    /// many VM instructions, guarding against infinite loops.
+    pub fn with_instruction_limit(mut self, count: u32) -> Self {
+        self.instruction_limit = Some(count);
+        self
+    }
+
+    /// A cheap fingerprint of this configuration, used to decide whether a
+    /// cached Lua interpreter built from an older `SandboxConfig` is still
+    /// valid.
+    pub(crate) fn fingerprint(&self) -> u64 {
+        (self.stdlib.bits() as u64) ^ self.memory_limit.unwrap_or(0) as u64
+            ^ self.instruction_limit.unwrap_or(0) as u64
+    }
+
+    pub(crate) fn build_lua(&self) -> Lua {
+        let lua = Lua::new_with(self.stdlib, LuaOptions::default())
+            .expect("Unable to construct sandboxed Lua interpreter.");
+
+        if let Some(limit) = self.memory_limit {
+            lua.set_memory_limit(limit)
+                .expect("Unable to set Lua memory limit.");
+        }
+
+        if let Some(limit) = self.instruction_limit {
+            lua.set_hook(
+                HookTriggers {
+                    every_nth_instruction: Some(limit),
+                    ..Default::default()
+                },
+                move |_lua, _debug| {
+                    Err(mlua::Error::RuntimeError(
+                        "template exceeded its instruction budget".to_string(),
+                    ))
+                },
+            );
+        }
+
+        lua
+    }
+}
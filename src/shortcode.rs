@@ -0,0 +1,86 @@
+use std::collections::HashMap;
+
+use lol_html::{HtmlRewriter, Settings, element, html_content::ContentType};
+
+use crate::LawlError;
+
+/// Expands every `<shortcode name="..." ...>` invocation in `template`
+/// against the fragments registered in `shortcodes`, before the `<lua>` pass
+/// runs. This is the static-site-generator shortcode pattern: one definition,
+/// many parameterized call sites.
+///
+/// Attributes passed to the invocation (other than `name`) are bound as Lua
+/// globals ahead of the fragment body, via an injected `<lua>` tag, so the
+/// fragment's own `<lua>` tags can read them by name.
+pub(crate) fn expand(
+    template: &str,
+    shortcodes: &HashMap<String, String>,
+) -> Result<String, LawlError> {
+    let mut buffer = vec![];
+
+    let mut rewriter = HtmlRewriter::new(
+        Settings {
+            element_content_handlers: vec![element!("shortcode", |el| {
+                let Some(name) = el.get_attribute("name") else {
+                    el.remove();
+                    return Ok(());
+                };
+
+                let Some(fragment) = shortcodes.get(&name) else {
+                    el.remove();
+                    return Ok(());
+                };
+
+                let bindings: String = el
+                    .attributes()
+                    .iter()
+                    .filter(|attribute| attribute.name() != "name")
+                    .map(|attribute| {
+                        format!(
+                            "{} = \"{}\"; ",
+                            attribute.name(),
+                            escape_attribute_value(&attribute.value())
+                        )
+                    })
+                    .collect();
+
+                let expanded = if bindings.is_empty() {
+                    fragment.clone()
+                } else {
+                    format!("<lua code='{bindings}'></lua>{fragment}")
+                };
+
+                el.replace(&expanded, ContentType::Html);
+
+                Ok(())
+            })],
+            ..Settings::new()
+        },
+        |c: &[u8]| buffer.extend_from_slice(c),
+    );
+
+    rewriter
+        .write(template.as_bytes())
+        .map_err(|error| LawlError::HtmlRewrite(error.to_string()))?;
+    rewriter
+        .end()
+        .map_err(|error| LawlError::HtmlRewrite(error.to_string()))?;
+
+    Ok(String::from_utf8(buffer)?)
+}
+
+/// Escapes an attribute value so it is safe to splice both as a Lua string
+/// literal (`"..."`) and, once that literal is embedded in the injected
+/// `<lua code='...'>` tag, as the contents of a single-quoted HTML
+/// attribute. Lua-escaping runs first so the quote/backslash it introduces
+/// can't itself be misread as HTML syntax; HTML-escaping then neutralizes
+/// `'`, `&`, and `<` so the value can't close the outer attribute or open a
+/// new tag when the expanded template is re-parsed by the `<lua>` rewriter.
+fn escape_attribute_value(value: &str) -> String {
+    let lua_escaped = value.replace('\\', "\\\\").replace('"', "\\\"");
+
+    lua_escaped
+        .replace('&', "&amp;")
+        .replace('\'', "&#39;")
+        .replace('<', "&lt;")
+}